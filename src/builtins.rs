@@ -0,0 +1,124 @@
+//! Built-in, dynamically-evaluated predefined macros: `__LINE__`, `__FILE__`, `__VERSION__`.
+//!
+//! Unlike a regular `#define`, these are not fixed strings sitting in `defined_syms`: they must be
+//! re-evaluated at every expansion site, tracking `Parser`’s position (adjusted by any `#line`
+//! directive already interpreted) and the most recent `#version`. A user `#define`/`#undef` of one
+//! of these names still goes through the configured `DefineMethod` as normal and, once it
+//! succeeds, takes precedence over the built-in, since `defined_syms` is always consulted first.
+
+use crate::syntax::LineDirective;
+use crate::Preprocessor;
+
+impl Preprocessor {
+  /// Map a physical line, as tracked by `Parser`, to the line `__LINE__` should report, taking
+  /// any already-interpreted `#line` directive into account.
+  pub(crate) fn report_line(&self, physical_line: usize) -> i64 {
+    physical_line as i64 + self.line_offset
+  }
+
+  /// Interpret a `#line` directive: remap the line numbering subsequent `__LINE__` expansions
+  /// report and, if given, update the source-string number `__FILE__` falls back to outside an
+  /// active `#include`. `directive_line` is the physical line the directive itself is on; a
+  /// `#line N` means the line right after it is numbered `N`.
+  pub(crate) fn apply_line_directive(&mut self, directive: &LineDirective, directive_line: usize) {
+    self.line_offset = directive.line as i64 - (directive_line as i64 + 1);
+
+    if let Some(source_string_number) = directive.source_string_number {
+      self.source_string_number = Some(source_string_number);
+    }
+  }
+
+  /// Interpret a `#version` directive for `__VERSION__` purposes.
+  pub(crate) fn apply_version_directive(&mut self, version: u16) {
+    self.version = Some(version);
+  }
+
+  /// Value of a dynamically-evaluated built-in macro at `reported_line`, or `None` if `ident`
+  /// does not name one.
+  pub(crate) fn dynamic_builtin_value(&self, ident: &str, reported_line: i64) -> Option<String> {
+    match ident {
+      "__LINE__" => Some(reported_line.to_string()),
+      "__FILE__" => Some(self.current_file()),
+      "__VERSION__" => self.version.map(|version| version.to_string()),
+      _ => None,
+    }
+  }
+
+  /// Current source’s `__FILE__` value: the canonical name of the innermost active `#include`, or
+  /// the source-string number set by the last `#line N M` when no include is active.
+  fn current_file(&self) -> String {
+    match self.include_stack.last() {
+      Some(name) => name.clone(),
+      None => self.source_string_number.unwrap_or(0).to_string(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::PreprocessorOpt;
+
+  #[test]
+  fn line_reports_physical_line_without_line_directive() {
+    let pp = Preprocessor::new(PreprocessorOpt::default());
+    assert_eq!(pp.dynamic_builtin_value("__LINE__", pp.report_line(5)), Some("5".to_owned()));
+  }
+
+  #[test]
+  fn line_directive_remaps_subsequent_lines() {
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+
+    // `#line 100` on physical line 3 means physical line 4 is reported as 100.
+    pp.apply_line_directive(
+      &LineDirective {
+        line: 100,
+        source_string_number: None,
+      },
+      3,
+    );
+
+    assert_eq!(pp.report_line(4), 100);
+    assert_eq!(pp.report_line(5), 101);
+  }
+
+  #[test]
+  fn file_falls_back_to_source_string_number_outside_include() {
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+
+    assert_eq!(pp.dynamic_builtin_value("__FILE__", 1), Some("0".to_owned()));
+
+    pp.apply_line_directive(
+      &LineDirective {
+        line: 1,
+        source_string_number: Some(2),
+      },
+      0,
+    );
+
+    assert_eq!(pp.dynamic_builtin_value("__FILE__", 1), Some("2".to_owned()));
+  }
+
+  #[test]
+  fn file_reports_innermost_include_over_source_string_number() {
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+    pp.include_stack.push("a.glsl".to_owned());
+
+    assert_eq!(pp.dynamic_builtin_value("__FILE__", 1), Some("a.glsl".to_owned()));
+  }
+
+  #[test]
+  fn version_is_none_until_a_version_directive_is_seen() {
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+    assert_eq!(pp.dynamic_builtin_value("__VERSION__", 1), None);
+
+    pp.apply_version_directive(450);
+    assert_eq!(pp.dynamic_builtin_value("__VERSION__", 1), Some("450".to_owned()));
+  }
+
+  #[test]
+  fn unknown_identifier_is_not_a_builtin() {
+    let pp = Preprocessor::new(PreprocessorOpt::default());
+    assert_eq!(pp.dynamic_builtin_value("NOT_A_BUILTIN", 1), None);
+  }
+}
@@ -0,0 +1,662 @@
+//! Constant-expression evaluator for `#if`/`#elseif` conditions.
+//!
+//! This mirrors, on a much smaller scale, what glsl-lang-pp’s `ExprEvaluator` does: resolve the
+//! `defined` operator, run the condition through the macro expansion engine, tokenize the result,
+//! then run a precedence-climbing parser over the tokens to produce the signed 64-bit value that
+//! decides whether the branch is taken.
+
+use crate::{Preprocessor, PreprocessorError};
+
+impl Preprocessor {
+  /// Evaluate a `#if`/`#elseif` condition and return whether its branch should be taken.
+  ///
+  /// Any error raised while evaluating (e.g. a division by zero in a live subexpression, or a
+  /// malformed macro invocation) is pushed onto `runtime_errors` and the condition is considered
+  /// false.
+  pub(crate) fn eval_condition(&mut self, condition: &str, reported_line: i64) -> bool {
+    let with_defined_resolved = self.resolve_defined_operator(condition);
+
+    let expanded = match self.expand_macros(&with_defined_resolved, reported_line) {
+      Ok(expanded) => expanded,
+      Err(error) => {
+        self.runtime_errors.push(error);
+        return false;
+      }
+    };
+
+    let tokens = tokenize(&expanded);
+
+    match ExprEvaluator::new(&tokens).eval() {
+      Ok(value) => value != 0,
+      Err(error) => {
+        self.runtime_errors.push(error);
+        false
+      }
+    }
+  }
+
+  /// Resolve every `defined X` / `defined(X)` operand against `defined_syms`, *before* any macro
+  /// expansion takes place, replacing it with `1` or `0`. Every other identifier is left alone for
+  /// [`Preprocessor::expand_macros`] to substitute.
+  fn resolve_defined_operator(&self, condition: &str) -> String {
+    let chars: Vec<char> = condition.chars().collect();
+    let mut out = String::with_capacity(chars.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+      let c = chars[i];
+
+      if c.is_alphabetic() || c == '_' {
+        let start = i;
+
+        while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+          i += 1;
+        }
+
+        let ident: String = chars[start..i].iter().collect();
+
+        if ident == "defined" {
+          let (is_defined, end) = self.read_defined_operand(&chars, i);
+          out.push(if is_defined { '1' } else { '0' });
+          i = end;
+        } else {
+          out.push_str(&ident);
+        }
+      } else {
+        out.push(c);
+        i += 1;
+      }
+    }
+
+    out
+  }
+
+  /// Read the operand of a `defined` operator starting right after the `defined` keyword, in
+  /// either its `defined X` or `defined(X)` form. Return whether the operand is currently defined
+  /// and the index right after the whole operator.
+  fn read_defined_operand(&self, chars: &[char], mut i: usize) -> (bool, usize) {
+    while i < chars.len() && chars[i].is_whitespace() {
+      i += 1;
+    }
+
+    let parenthesized = i < chars.len() && chars[i] == '(';
+
+    if parenthesized {
+      i += 1;
+
+      while i < chars.len() && chars[i].is_whitespace() {
+        i += 1;
+      }
+    }
+
+    let name_start = i;
+
+    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+      i += 1;
+    }
+
+    let name: String = chars[name_start..i].iter().collect();
+
+    if parenthesized {
+      while i < chars.len() && chars[i].is_whitespace() {
+        i += 1;
+      }
+
+      if i < chars.len() && chars[i] == ')' {
+        i += 1;
+      }
+    }
+
+    (self.defined_syms.contains_key(&name), i)
+  }
+}
+
+/// A lexical token recognized inside a preprocessor constant expression.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Int(i64),
+  Ident(String),
+  Not,
+  Complement,
+  Plus,
+  Minus,
+  Star,
+  Slash,
+  Percent,
+  Shl,
+  Shr,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  EqEq,
+  Ne,
+  Amp,
+  Caret,
+  Pipe,
+  AmpAmp,
+  PipePipe,
+  Question,
+  Colon,
+  LParen,
+  RParen,
+}
+
+/// Tokenize a constant-expression string. Unrecognized characters (e.g. stray whitespace) are
+/// silently skipped; anything left that doesn’t look like a C operator is simply dropped, since a
+/// malformed leftover will surface as a parse error when the evaluator consumes the tokens.
+fn tokenize(input: &str) -> Vec<Token> {
+  let chars: Vec<char> = input.chars().collect();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+
+  macro_rules! two_char {
+    ($second:expr, $both:expr, $single:expr) => {{
+      if chars.get(i + 1) == Some(&$second) {
+        i += 2;
+        $both
+      } else {
+        i += 1;
+        $single
+      }
+    }};
+  }
+
+  while i < chars.len() {
+    let c = chars[i];
+
+    if c.is_whitespace() {
+      i += 1;
+    } else if c.is_ascii_digit() {
+      let start = i;
+
+      if c == '0' && matches!(chars.get(i + 1), Some('x') | Some('X')) {
+        i += 2;
+        let digits_start = i;
+
+        while i < chars.len() && chars[i].is_ascii_hexdigit() {
+          i += 1;
+        }
+
+        let value = i64::from_str_radix(&chars[digits_start..i].iter().collect::<String>(), 16).unwrap_or(0);
+        tokens.push(Token::Int(value));
+      } else {
+        while i < chars.len() && chars[i].is_ascii_digit() {
+          i += 1;
+        }
+
+        let value: i64 = chars[start..i].iter().collect::<String>().parse().unwrap_or(0);
+        tokens.push(Token::Int(value));
+      }
+
+      // Skip integer suffixes such as `u`, `U`, `l`, `L`.
+      while i < chars.len() && matches!(chars[i], 'u' | 'U' | 'l' | 'L') {
+        i += 1;
+      }
+    } else if c.is_alphabetic() || c == '_' {
+      let start = i;
+
+      while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+        i += 1;
+      }
+
+      tokens.push(Token::Ident(chars[start..i].iter().collect()));
+    } else {
+      match c {
+        '(' => {
+          tokens.push(Token::LParen);
+          i += 1;
+        }
+        ')' => {
+          tokens.push(Token::RParen);
+          i += 1;
+        }
+        '?' => {
+          tokens.push(Token::Question);
+          i += 1;
+        }
+        ':' => {
+          tokens.push(Token::Colon);
+          i += 1;
+        }
+        '~' => {
+          tokens.push(Token::Complement);
+          i += 1;
+        }
+        '+' => {
+          tokens.push(Token::Plus);
+          i += 1;
+        }
+        '-' => {
+          tokens.push(Token::Minus);
+          i += 1;
+        }
+        '*' => {
+          tokens.push(Token::Star);
+          i += 1;
+        }
+        '/' => {
+          tokens.push(Token::Slash);
+          i += 1;
+        }
+        '%' => {
+          tokens.push(Token::Percent);
+          i += 1;
+        }
+        '^' => {
+          tokens.push(Token::Caret);
+          i += 1;
+        }
+        '!' => {
+          let tok = two_char!('=', Token::Ne, Token::Not);
+          tokens.push(tok);
+        }
+        '=' => {
+          if chars.get(i + 1) == Some(&'=') {
+            tokens.push(Token::EqEq);
+            i += 2;
+          } else {
+            // A stray `=` cannot appear in a constant expression; skip it and let the missing
+            // operand surface as a parse error.
+            i += 1;
+          }
+        }
+        '<' => {
+          if chars.get(i + 1) == Some(&'<') {
+            tokens.push(Token::Shl);
+            i += 2;
+          } else {
+            let tok = two_char!('=', Token::Le, Token::Lt);
+            tokens.push(tok);
+          }
+        }
+        '>' => {
+          if chars.get(i + 1) == Some(&'>') {
+            tokens.push(Token::Shr);
+            i += 2;
+          } else {
+            let tok = two_char!('=', Token::Ge, Token::Gt);
+            tokens.push(tok);
+          }
+        }
+        '&' => {
+          let tok = two_char!('&', Token::AmpAmp, Token::Amp);
+          tokens.push(tok);
+        }
+        '|' => {
+          let tok = two_char!('|', Token::PipePipe, Token::Pipe);
+          tokens.push(tok);
+        }
+        _ => {
+          i += 1;
+        }
+      }
+    }
+  }
+
+  tokens
+}
+
+/// Precedence-climbing evaluator over a token stream produced by [`tokenize`].
+struct ExprEvaluator<'t> {
+  tokens: &'t [Token],
+  pos: usize,
+}
+
+impl<'t> ExprEvaluator<'t> {
+  fn new(tokens: &'t [Token]) -> Self {
+    ExprEvaluator { tokens, pos: 0 }
+  }
+
+  /// Evaluate the whole token stream as a single constant expression.
+  fn eval(&mut self) -> Result<i64, PreprocessorError> {
+    let value = self.parse_ternary(true)?;
+
+    if self.pos != self.tokens.len() {
+      return Err(PreprocessorError::CodeDriven(
+        "trailing tokens in constant expression".to_owned(),
+      ));
+    }
+
+    Ok(value)
+  }
+
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn bump(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.pos).cloned();
+
+    if token.is_some() {
+      self.pos += 1;
+    }
+
+    token
+  }
+
+  /// `cond ? then : else`; only the taken branch is evaluated live.
+  fn parse_ternary(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let cond = self.parse_logical_or(live)?;
+
+    if matches!(self.peek(), Some(Token::Question)) {
+      self.bump();
+
+      let then_value = self.parse_ternary(live && cond != 0)?;
+
+      if !matches!(self.bump(), Some(Token::Colon)) {
+        return Err(PreprocessorError::CodeDriven(
+          "expected ':' in ternary conditional expression".to_owned(),
+        ));
+      }
+
+      let else_value = self.parse_ternary(live && cond == 0)?;
+
+      Ok(if cond != 0 { then_value } else { else_value })
+    } else {
+      Ok(cond)
+    }
+  }
+
+  fn parse_logical_or(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_logical_and(live)?;
+
+    while matches!(self.peek(), Some(Token::PipePipe)) {
+      self.bump();
+      let right = self.parse_logical_and(live && left == 0)?;
+      left = ((left != 0) || (right != 0)) as i64;
+    }
+
+    Ok(left)
+  }
+
+  fn parse_logical_and(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_bitor(live)?;
+
+    while matches!(self.peek(), Some(Token::AmpAmp)) {
+      self.bump();
+      let right = self.parse_bitor(live && left != 0)?;
+      left = ((left != 0) && (right != 0)) as i64;
+    }
+
+    Ok(left)
+  }
+
+  fn parse_bitor(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_bitxor(live)?;
+
+    while matches!(self.peek(), Some(Token::Pipe)) {
+      self.bump();
+      left |= self.parse_bitxor(live)?;
+    }
+
+    Ok(left)
+  }
+
+  fn parse_bitxor(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_bitand(live)?;
+
+    while matches!(self.peek(), Some(Token::Caret)) {
+      self.bump();
+      left ^= self.parse_bitand(live)?;
+    }
+
+    Ok(left)
+  }
+
+  fn parse_bitand(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_equality(live)?;
+
+    while matches!(self.peek(), Some(Token::Amp)) {
+      self.bump();
+      left &= self.parse_equality(live)?;
+    }
+
+    Ok(left)
+  }
+
+  fn parse_equality(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_relational(live)?;
+
+    loop {
+      match self.peek() {
+        Some(Token::EqEq) => {
+          self.bump();
+          let right = self.parse_relational(live)?;
+          left = (left == right) as i64;
+        }
+        Some(Token::Ne) => {
+          self.bump();
+          let right = self.parse_relational(live)?;
+          left = (left != right) as i64;
+        }
+        _ => break,
+      }
+    }
+
+    Ok(left)
+  }
+
+  fn parse_relational(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_shift(live)?;
+
+    loop {
+      match self.peek() {
+        Some(Token::Lt) => {
+          self.bump();
+          let right = self.parse_shift(live)?;
+          left = (left < right) as i64;
+        }
+        Some(Token::Le) => {
+          self.bump();
+          let right = self.parse_shift(live)?;
+          left = (left <= right) as i64;
+        }
+        Some(Token::Gt) => {
+          self.bump();
+          let right = self.parse_shift(live)?;
+          left = (left > right) as i64;
+        }
+        Some(Token::Ge) => {
+          self.bump();
+          let right = self.parse_shift(live)?;
+          left = (left >= right) as i64;
+        }
+        _ => break,
+      }
+    }
+
+    Ok(left)
+  }
+
+  fn parse_shift(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_additive(live)?;
+
+    loop {
+      match self.peek() {
+        Some(Token::Shl) => {
+          self.bump();
+          let right = self.parse_additive(live)?;
+          left = left.wrapping_shl(right as u32);
+        }
+        Some(Token::Shr) => {
+          self.bump();
+          let right = self.parse_additive(live)?;
+          left = left.wrapping_shr(right as u32);
+        }
+        _ => break,
+      }
+    }
+
+    Ok(left)
+  }
+
+  fn parse_additive(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_multiplicative(live)?;
+
+    loop {
+      match self.peek() {
+        Some(Token::Plus) => {
+          self.bump();
+          left = left.wrapping_add(self.parse_multiplicative(live)?);
+        }
+        Some(Token::Minus) => {
+          self.bump();
+          left = left.wrapping_sub(self.parse_multiplicative(live)?);
+        }
+        _ => break,
+      }
+    }
+
+    Ok(left)
+  }
+
+  fn parse_multiplicative(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    let mut left = self.parse_unary(live)?;
+
+    loop {
+      match self.peek() {
+        Some(Token::Star) => {
+          self.bump();
+          left = left.wrapping_mul(self.parse_unary(live)?);
+        }
+        Some(Token::Slash) => {
+          self.bump();
+          let right = self.parse_unary(live)?;
+
+          if right == 0 {
+            if live {
+              return Err(PreprocessorError::DivisionByZero);
+            }
+
+            left = 0;
+          } else {
+            left = left.wrapping_div(right);
+          }
+        }
+        Some(Token::Percent) => {
+          self.bump();
+          let right = self.parse_unary(live)?;
+
+          if right == 0 {
+            if live {
+              return Err(PreprocessorError::DivisionByZero);
+            }
+
+            left = 0;
+          } else {
+            left = left.wrapping_rem(right);
+          }
+        }
+        _ => break,
+      }
+    }
+
+    Ok(left)
+  }
+
+  fn parse_unary(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    match self.peek() {
+      Some(Token::Not) => {
+        self.bump();
+        Ok((self.parse_unary(live)? == 0) as i64)
+      }
+      Some(Token::Complement) => {
+        self.bump();
+        Ok(!self.parse_unary(live)?)
+      }
+      Some(Token::Minus) => {
+        self.bump();
+        Ok(self.parse_unary(live)?.wrapping_neg())
+      }
+      Some(Token::Plus) => {
+        self.bump();
+        self.parse_unary(live)
+      }
+      _ => self.parse_primary(live),
+    }
+  }
+
+  fn parse_primary(&mut self, live: bool) -> Result<i64, PreprocessorError> {
+    match self.bump() {
+      Some(Token::Int(value)) => Ok(value),
+      // An identifier surviving macro expansion is unknown; C mandates it evaluates to 0.
+      Some(Token::Ident(_)) => Ok(0),
+      Some(Token::LParen) => {
+        let value = self.parse_ternary(live)?;
+
+        if !matches!(self.bump(), Some(Token::RParen)) {
+          return Err(PreprocessorError::CodeDriven(
+            "unbalanced parentheses in constant expression".to_owned(),
+          ));
+        }
+
+        Ok(value)
+      }
+      other => Err(PreprocessorError::CodeDriven(format!(
+        "unexpected token in constant expression: {:?}",
+        other
+      ))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{Preprocessor, PreprocessorError, PreprocessorOpt};
+
+  fn preprocessor() -> Preprocessor {
+    Preprocessor::new(PreprocessorOpt::default())
+  }
+
+  #[test]
+  fn logical_and_short_circuits_before_division_by_zero() {
+    let mut pp = preprocessor();
+    assert!(!pp.eval_condition("0 && (1 / 0)", 1));
+    assert!(pp.runtime_errors.is_empty());
+  }
+
+  #[test]
+  fn logical_or_short_circuits_before_division_by_zero() {
+    let mut pp = preprocessor();
+    assert!(pp.eval_condition("1 || (1 / 0)", 1));
+    assert!(pp.runtime_errors.is_empty());
+  }
+
+  #[test]
+  fn ternary_short_circuits_the_untaken_branch() {
+    let mut pp = preprocessor();
+    assert!(pp.eval_condition("1 ? 1 : (1 / 0)", 1));
+    assert!(pp.runtime_errors.is_empty());
+
+    let mut pp = preprocessor();
+    assert!(!pp.eval_condition("0 ? (1 / 0) : 0", 1));
+    assert!(pp.runtime_errors.is_empty());
+  }
+
+  #[test]
+  fn live_division_by_zero_raises_and_is_false() {
+    let mut pp = preprocessor();
+    assert!(!pp.eval_condition("1 / 0", 1));
+    assert_eq!(pp.runtime_errors, vec![PreprocessorError::DivisionByZero]);
+  }
+
+  #[test]
+  fn operator_precedence_matches_c() {
+    let mut pp = preprocessor();
+    // `*` binds tighter than `+`, which binds tighter than `==`.
+    assert!(pp.eval_condition("1 + 2 * 3 == 7", 1));
+    assert!(pp.runtime_errors.is_empty());
+  }
+
+  #[test]
+  fn defined_bare_and_parenthesized_forms() {
+    let mut pp = preprocessor();
+    pp.define_sym("FOO".to_owned(), crate::Defined::Object("1".to_owned()));
+
+    assert!(pp.eval_condition("defined FOO", 1));
+    assert!(pp.eval_condition("defined(FOO)", 1));
+    assert!(!pp.eval_condition("defined BAR", 1));
+    assert!(pp.runtime_errors.is_empty());
+  }
+}
@@ -0,0 +1,668 @@
+//! Streaming event API.
+//!
+//! Rather than folding straight into a single `String`, [`Preprocessor::events`] returns an
+//! iterator of [`Event`]s, the way glsl-lang-pp’s `Event`/`OutputToken` do: emitted output text
+//! spans (with the line/col they originated from, via [`crate::parser::Parser`]), recognized
+//! directives passed through to the consumer (`#version`, `#extension`, `#pragma`, `#line`, and
+//! `#include` when no resolver is configured), and [`PreprocessorError`]s, all in source order.
+//! This lets a consumer splice in include resolution or hand tokens to a GLSL parser without
+//! re-scanning the output, and react to a `#version`/`#extension` where it comes up in the event
+//! stream. The whole input is preprocessed before the iterator is returned. [`Preprocessor::run`] is a
+//! convenience built on top that folds the events into a final translation-unit string plus the
+//! collected errors.
+
+use crate::parser::Parser;
+use crate::syntax::{
+  Directive, DefineDirective, ExtensionBehavior, ExtensionDirective, ExtensionName, IncludeDirective,
+  LineDirective, Path, PragmaDirective, VersionDirective, VersionProfile,
+};
+use crate::{Defined, IncludeResolver, Preprocessor, PreprocessorError};
+
+/// Canonical name reported to an [`IncludeResolver`] for a top-level `#include`, i.e. one that
+/// does not appear inside an already-included source.
+const ROOT_SOURCE_NAME: &str = "<input>";
+
+/// A span of output text, already macro-expanded, along with the line/column it originated from
+/// in its own source: the input that was handed to [`Preprocessor::events`], or the `#include`d
+/// source it was read from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputToken {
+  /// The expanded text to copy into the translation unit.
+  pub text: String,
+  /// Line the span started on, 1-based.
+  pub line: usize,
+  /// Column the span started on, 1-based.
+  pub col: usize,
+}
+
+/// An event produced while preprocessing, in source order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+  /// A span of output text to copy into the translation unit.
+  Output(OutputToken),
+  /// A recognized directive passed through to the consumer; `#line`, `#version` and
+  /// `#pragma once` have also updated the preprocessor's own state. See the crate-level docs.
+  Directive(Directive),
+  /// An error encountered while preprocessing.
+  Error(PreprocessorError),
+}
+
+impl Preprocessor {
+  /// Preprocess the whole of `input` and return an iterator over the resulting [`Event`]s, one per
+  /// output span, pass-through directive, or error, in source order. All events are produced
+  /// before this returns; the iterator does not preprocess lazily.
+  ///
+  /// When `resolver` is `Some`, a reached `#include` is resolved through it and its contents are
+  /// preprocessed in place of the directive, instead of being passed through as
+  /// `Event::Directive(Directive::Include(..))`; see [`IncludeResolver`].
+  pub fn events<'p>(
+    &'p mut self,
+    input: &'p str,
+    resolver: Option<&'p dyn IncludeResolver>,
+  ) -> impl Iterator<Item = Event> + 'p {
+    let mut sink = Sink::new(resolver);
+    self.collect_events(input, &mut sink);
+    sink.events.into_iter()
+  }
+
+  /// Run the preprocessor on `input`, folding [`Preprocessor::events`] into a final
+  /// translation-unit string and the errors encountered along the way.
+  pub fn run(mut self, input: &str, resolver: Option<&dyn IncludeResolver>) -> (String, Vec<PreprocessorError>) {
+    let mut output = String::new();
+    let mut errors = Vec::new();
+
+    for event in self.events(input, resolver) {
+      match event {
+        Event::Output(token) => output.push_str(&token.text),
+        Event::Directive(directive) => output.push_str(&render_directive(&directive)),
+        Event::Error(error) => errors.push(error),
+      }
+    }
+
+    (output, errors)
+  }
+
+  fn collect_events(&mut self, input: &str, sink: &mut Sink) {
+    let mut parser = Parser::new(input);
+    let mut current_line = String::new();
+    let mut pending = PendingLine::default();
+    let mut line_start = parser.line();
+    let mut col_start = parser.col();
+
+    loop {
+      if current_line.is_empty() {
+        line_start = parser.line();
+        col_start = parser.col();
+      }
+
+      match parser.char() {
+        Some('\n') => {
+          self.accept_line(&current_line, line_start, col_start, false, &mut pending, sink);
+          current_line.clear();
+        }
+
+        Some(c) => current_line.push(c),
+
+        None => {
+          if !current_line.is_empty() {
+            self.accept_line(&current_line, line_start, col_start, true, &mut pending, sink);
+          } else if !pending.is_empty() {
+            let (text, line, col) = pending.take();
+            self.process_line(&text, line, col, sink);
+          }
+
+          break;
+        }
+      }
+    }
+  }
+
+  /// Fold one more physical line into the logical line being accumulated in `pending`, then
+  /// process it once that logical line is complete. A directive is always a single physical line
+  /// on its own and is processed immediately; ordinary text is held back while it contains a
+  /// function-like macro invocation whose argument list spans further physical lines (unless
+  /// `force_flush`, e.g. at end of input), so that [`Preprocessor::expand_macros`] sees the whole
+  /// invocation at once.
+  fn accept_line(
+    &mut self,
+    current_line: &str,
+    line_start: usize,
+    col_start: usize,
+    force_flush: bool,
+    pending: &mut PendingLine,
+    sink: &mut Sink,
+  ) {
+    if current_line.trim_start().starts_with('#') {
+      if !pending.is_empty() {
+        let (text, line, col) = pending.take();
+        self.process_line(&text, line, col, sink);
+      }
+
+      self.process_line(current_line, line_start, col_start, sink);
+      return;
+    }
+
+    pending.push(current_line, line_start, col_start);
+
+    if force_flush || !self.has_unterminated_invocation(pending.text()) {
+      let (text, line, col) = pending.take();
+      self.process_line(&text, line, col, sink);
+    }
+  }
+
+  fn process_line(&mut self, raw_line: &str, line: usize, col: usize, sink: &mut Sink) {
+    let trimmed = raw_line.trim_start();
+
+    if let Some(rest) = trimmed.strip_prefix('#') {
+      self.process_directive(rest.trim(), line, sink);
+    } else if !self.is_ignoring() {
+      match self.expand_macros(raw_line, self.report_line(line)) {
+        Ok(text) => sink.events.push(Event::Output(OutputToken {
+          text: format!("{}\n", text),
+          line,
+          col,
+        })),
+        Err(error) => sink.events.push(Event::Error(error)),
+      }
+    }
+  }
+
+  fn process_directive(&mut self, rest: &str, line: usize, sink: &mut Sink) {
+    let (keyword, arg) = split_keyword(rest);
+    let reported_line = self.report_line(line);
+
+    // Conditionals drive `is_ignoring()` itself, so they must run whether or not we are currently
+    // ignoring code.
+    match keyword {
+      "if" => {
+        self.enter_if(arg, reported_line);
+        self.drain_runtime_errors(sink);
+        return;
+      }
+      "ifdef" => {
+        self.enter_ifdef(arg.trim());
+        self.drain_runtime_errors(sink);
+        return;
+      }
+      "ifndef" => {
+        self.enter_ifndef(arg.trim());
+        self.drain_runtime_errors(sink);
+        return;
+      }
+      "elseif" => {
+        self.enter_elseif(arg, reported_line);
+        self.drain_runtime_errors(sink);
+        return;
+      }
+      "else" => {
+        self.enter_else();
+        return;
+      }
+      "endif" => {
+        self.leave_conditional();
+        self.drain_runtime_errors(sink);
+        return;
+      }
+      _ => {}
+    }
+
+    if self.is_ignoring() {
+      return;
+    }
+
+    match keyword {
+      "define" => match parse_define(arg) {
+        Ok(DefineDirective::Object { ident, value }) => self.define_sym(ident, Defined::Object(value)),
+        Ok(DefineDirective::Function { ident, args, body }) => {
+          self.define_sym(ident, Defined::Function { arg: args, body })
+        }
+        Err(error) => sink.events.push(Event::Error(error)),
+      },
+
+      "undef" => self.undef_sym(arg.trim()),
+
+      "error" => self.raise_error(arg.trim().trim_matches('"').to_owned()),
+
+      "include" => match parse_include_path(arg) {
+        Ok(path) => self.process_include(IncludeDirective { path }, sink),
+        Err(error) => sink.events.push(Event::Error(error)),
+      },
+
+      "line" => match parse_line(arg) {
+        Ok(directive) => {
+          self.apply_line_directive(&directive, line);
+          sink.events.push(Event::Directive(Directive::Line(directive)));
+        }
+        Err(error) => sink.events.push(Event::Error(error)),
+      },
+
+      "pragma" => {
+        let directive = PragmaDirective {
+          command: arg.trim().to_owned(),
+        };
+        self.handle_pragma(&directive);
+        sink.events.push(Event::Directive(Directive::Pragma(directive)));
+      }
+
+      "version" => match parse_version(arg) {
+        Ok(directive) => {
+          self.apply_version_directive(directive.version);
+          sink.events.push(Event::Directive(Directive::Version(directive)));
+        }
+        Err(error) => sink.events.push(Event::Error(error)),
+      },
+
+      "extension" => match parse_extension(arg) {
+        Ok(directive) => sink.events.push(Event::Directive(Directive::Extension(directive))),
+        Err(error) => sink.events.push(Event::Error(error)),
+      },
+
+      other => sink.events.push(Event::Error(PreprocessorError::CodeDriven(format!(
+        "unknown preprocessor directive `#{}`",
+        other
+      )))),
+    }
+
+    self.drain_runtime_errors(sink);
+  }
+
+  /// Interpret a reached `#include`: with no resolver configured, pass it through untouched, as
+  /// before. With one configured, resolve it and recursively preprocess its contents in place of
+  /// the directive, resuming this source exactly where it left off once that’s done.
+  fn process_include(&mut self, directive: IncludeDirective, sink: &mut Sink) {
+    let Some(resolver) = sink.resolver else {
+      sink.events.push(Event::Directive(Directive::Include(directive)));
+      return;
+    };
+
+    let from = self.current_include().unwrap_or(ROOT_SOURCE_NAME).to_owned();
+
+    match self.push_include(resolver, &directive, &from) {
+      Ok(Some(contents)) => {
+        self.collect_events(&contents, sink);
+        self.pop_include();
+      }
+
+      // Already brought in under a `#pragma once`: skip silently, as if it had expanded to
+      // nothing.
+      Ok(None) => {}
+
+      Err(error) => sink.events.push(Event::Error(error)),
+    }
+  }
+
+  /// Move any error accumulated on `runtime_errors` while handling the directive just processed
+  /// into the event stream, in source order.
+  fn drain_runtime_errors(&mut self, sink: &mut Sink) {
+    for error in self.runtime_errors.drain(..) {
+      sink.events.push(Event::Error(error));
+    }
+  }
+}
+
+/// Where [`Preprocessor::collect_events`] and the methods it calls append produced events, along
+/// with the (optional) [`IncludeResolver`] configured for this run; bundled together since every
+/// one of those methods needs to thread both down to wherever a `#include` might be reached.
+struct Sink<'r> {
+  events: Vec<Event>,
+  resolver: Option<&'r dyn IncludeResolver>,
+}
+
+impl<'r> Sink<'r> {
+  fn new(resolver: Option<&'r dyn IncludeResolver>) -> Self {
+    Sink {
+      events: Vec::new(),
+      resolver,
+    }
+  }
+}
+
+/// A logical line being accumulated from one or more physical lines, for invocations of a
+/// function-like macro whose argument list spans more than one of them; see
+/// [`Preprocessor::accept_line`].
+#[derive(Default)]
+struct PendingLine {
+  text: String,
+  start_line: usize,
+  start_col: usize,
+}
+
+impl PendingLine {
+  fn is_empty(&self) -> bool {
+    self.text.is_empty()
+  }
+
+  fn text(&self) -> &str {
+    &self.text
+  }
+
+  /// Fold `line` in, recording `line_start`/`col_start` as the logical line's origin if this is
+  /// its first physical line.
+  fn push(&mut self, line: &str, line_start: usize, col_start: usize) {
+    if self.text.is_empty() {
+      self.start_line = line_start;
+      self.start_col = col_start;
+    } else {
+      self.text.push('\n');
+    }
+
+    self.text.push_str(line);
+  }
+
+  /// Take the accumulated text and its origin, resetting to empty.
+  fn take(&mut self) -> (String, usize, usize) {
+    (std::mem::take(&mut self.text), self.start_line, self.start_col)
+  }
+}
+
+/// Split a directive’s body, e.g. `define FOO 1`, into its keyword and the (left-trimmed) rest of
+/// the line.
+fn split_keyword(rest: &str) -> (&str, &str) {
+  match rest.find(char::is_whitespace) {
+    Some(idx) => (&rest[..idx], rest[idx..].trim_start()),
+    None => (rest, ""),
+  }
+}
+
+fn parse_define(arg: &str) -> Result<DefineDirective, PreprocessorError> {
+  let ident_end = arg
+    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+    .unwrap_or(arg.len());
+
+  if ident_end == 0 {
+    return Err(PreprocessorError::CodeDriven(
+      "expected an identifier after #define".to_owned(),
+    ));
+  }
+
+  let ident = arg[..ident_end].to_owned();
+  let rest = &arg[ident_end..];
+
+  if let Some(rest) = rest.strip_prefix('(') {
+    let close = rest.find(')').ok_or_else(|| {
+      PreprocessorError::CodeDriven(format!("unterminated parameter list in #define {}", ident))
+    })?;
+
+    let params_str = rest[..close].trim();
+    let args = if params_str.is_empty() {
+      Vec::new()
+    } else {
+      params_str.split(',').map(|p| p.trim().to_owned()).collect()
+    };
+
+    let body = rest[close + 1..].trim().to_owned();
+
+    Ok(DefineDirective::Function { ident, args, body })
+  } else {
+    Ok(DefineDirective::Object {
+      ident,
+      value: rest.trim().to_owned(),
+    })
+  }
+}
+
+fn parse_include_path(arg: &str) -> Result<Path, PreprocessorError> {
+  let trimmed = arg.trim();
+
+  if let Some(inner) = trimmed.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+    Ok(Path::Absolute(inner.to_owned()))
+  } else if let Some(inner) = trimmed.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+    Ok(Path::Relative(inner.to_owned()))
+  } else {
+    Err(PreprocessorError::CodeDriven(format!(
+      "malformed #include path `{}`",
+      trimmed
+    )))
+  }
+}
+
+fn parse_line(arg: &str) -> Result<LineDirective, PreprocessorError> {
+  let mut parts = arg.split_whitespace();
+
+  let line = parts
+    .next()
+    .and_then(|s| s.parse().ok())
+    .ok_or_else(|| PreprocessorError::CodeDriven(format!("malformed #line directive `{}`", arg)))?;
+
+  let source_string_number = parts.next().and_then(|s| s.parse().ok());
+
+  Ok(LineDirective {
+    line,
+    source_string_number,
+  })
+}
+
+fn parse_version(arg: &str) -> Result<VersionDirective, PreprocessorError> {
+  let mut parts = arg.split_whitespace();
+
+  let version = parts
+    .next()
+    .and_then(|s| s.parse().ok())
+    .ok_or_else(|| PreprocessorError::CodeDriven(format!("malformed #version directive `{}`", arg)))?;
+
+  let profile = match parts.next() {
+    Some("core") => Some(VersionProfile::Core),
+    Some("compatibility") => Some(VersionProfile::Compatibility),
+    Some("es") => Some(VersionProfile::ES),
+    Some(other) => {
+      return Err(PreprocessorError::CodeDriven(format!(
+        "unknown #version profile `{}`",
+        other
+      )))
+    }
+    None => None,
+  };
+
+  Ok(VersionDirective { version, profile })
+}
+
+fn parse_extension(arg: &str) -> Result<ExtensionDirective, PreprocessorError> {
+  let mut parts = arg.splitn(2, ':');
+  let name_str = parts.next().unwrap_or("").trim();
+
+  if name_str.is_empty() {
+    return Err(PreprocessorError::CodeDriven(
+      "expected an extension name after #extension".to_owned(),
+    ));
+  }
+
+  let name = if name_str == "all" {
+    ExtensionName::All
+  } else {
+    ExtensionName::Specific(name_str.to_owned())
+  };
+
+  let behavior = match parts.next().map(str::trim) {
+    Some("require") => Some(ExtensionBehavior::Require),
+    Some("enable") => Some(ExtensionBehavior::Enable),
+    Some("warn") => Some(ExtensionBehavior::Warn),
+    Some("disable") => Some(ExtensionBehavior::Disable),
+    Some(other) => {
+      return Err(PreprocessorError::CodeDriven(format!(
+        "unknown #extension behavior `{}`",
+        other
+      )))
+    }
+    None => None,
+  };
+
+  Ok(ExtensionDirective { name, behavior })
+}
+
+/// Render a pass-through directive back to source form, for [`Preprocessor::run`].
+fn render_directive(directive: &Directive) -> String {
+  match directive {
+    Directive::Include(IncludeDirective { path }) => match path {
+      Path::Absolute(p) => format!("#include <{}>\n", p),
+      Path::Relative(p) => format!("#include \"{}\"\n", p),
+    },
+
+    Directive::Line(LineDirective {
+      line,
+      source_string_number,
+    }) => match source_string_number {
+      Some(n) => format!("#line {} {}\n", line, n),
+      None => format!("#line {}\n", line),
+    },
+
+    Directive::Pragma(PragmaDirective { command }) => format!("#pragma {}\n", command),
+
+    Directive::Version(VersionDirective { version, profile }) => match profile {
+      Some(VersionProfile::Core) => format!("#version {} core\n", version),
+      Some(VersionProfile::Compatibility) => format!("#version {} compatibility\n", version),
+      Some(VersionProfile::ES) => format!("#version {} es\n", version),
+      None => format!("#version {}\n", version),
+    },
+
+    Directive::Extension(ExtensionDirective { name, behavior }) => {
+      let name = match name {
+        ExtensionName::All => "all".to_owned(),
+        ExtensionName::Specific(n) => n.clone(),
+      };
+
+      match behavior {
+        Some(ExtensionBehavior::Require) => format!("#extension {} : require\n", name),
+        Some(ExtensionBehavior::Enable) => format!("#extension {} : enable\n", name),
+        Some(ExtensionBehavior::Warn) => format!("#extension {} : warn\n", name),
+        Some(ExtensionBehavior::Disable) => format!("#extension {} : disable\n", name),
+        None => format!("#extension {}\n", name),
+      }
+    }
+
+    // `#define`/`#undef`/the conditional family/`#error` are always interpreted internally and
+    // never surface as an `Event::Directive`.
+    _ => String::new(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::{HashMap, HashSet};
+
+  use super::*;
+  use crate::{DefineMethod, PreprocessorOpt};
+
+  fn preprocessor() -> Preprocessor {
+    Preprocessor {
+      opt: PreprocessorOpt {
+        define_method: DefineMethod::Override,
+        max_include_depth: 200,
+      },
+      runtime_errors: Vec::new(),
+      defined_syms: HashMap::new(),
+      conditional_stack: Vec::new(),
+      conditional_matched: Vec::new(),
+      include_stack: Vec::new(),
+      pragma_once_seen: HashSet::new(),
+      line_offset: 0,
+      source_string_number: None,
+      version: None,
+    }
+  }
+
+  #[test]
+  fn object_macro_expansion() {
+    let (output, errors) = preprocessor().run("#define FOO 42\nint x = FOO;\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int x = 42;\n");
+  }
+
+  #[test]
+  fn function_macro_expansion() {
+    let (output, errors) = preprocessor().run("#define ADD(a, b) ((a) + (b))\nint x = ADD(1, 2);\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int x = ((1) + (2));\n");
+  }
+
+  #[test]
+  fn function_macro_invocation_spanning_multiple_lines() {
+    let (output, errors) = preprocessor().run("#define ADD(a, b) a+b\nint x = ADD(1,\n2);\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int x = 1+2;\n");
+  }
+
+  #[test]
+  fn if_else_conditional() {
+    let (output, errors) =
+      preprocessor().run("#define FOO 1\n#if FOO == 1\nint a;\n#else\nint b;\n#endif\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int a;\n");
+  }
+
+  #[test]
+  fn ifdef_elseif_else_chain() {
+    let (output, errors) = preprocessor().run("#ifdef MISSING\na\n#elseif 0\nb\n#else\nc\n#endif\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "c\n");
+  }
+
+  #[test]
+  fn elseif_condition_expands_macros() {
+    let (output, errors) =
+      preprocessor().run("#define FOO 1\n#if 0\na\n#elseif FOO == 1\nb\n#else\nc\n#endif\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "b\n");
+  }
+
+  #[test]
+  fn passthrough_directives() {
+    let (output, errors) =
+      preprocessor().run("#version 450 core\n#include <foo.glsl>\nvoid main() {}\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "#version 450 core\n#include <foo.glsl>\nvoid main() {}\n");
+  }
+
+  #[test]
+  fn builtin_line_and_version() {
+    let (output, errors) = preprocessor().run("#version 450 core\nint v = __VERSION__;\nint l = __LINE__;\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "#version 450 core\nint v = 450;\nint l = 3;\n");
+  }
+
+  #[test]
+  fn builtin_line_remapped_by_line_directive() {
+    let (output, errors) = preprocessor().run("#line 10 2\nint l = __LINE__;\nint f = __FILE__;\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "#line 10 2\nint l = 10;\nint f = 2;\n");
+  }
+
+  struct StubResolver;
+
+  impl crate::IncludeResolver for StubResolver {
+    fn resolve(&self, path: &crate::syntax::Path, _from: &str) -> Result<(String, String), std::io::Error> {
+      let name = match path {
+        crate::syntax::Path::Absolute(p) | crate::syntax::Path::Relative(p) => p.clone(),
+      };
+
+      match name.as_str() {
+        "foo.glsl" => Ok((name, "int foo() { return __LINE__; }\n".to_owned())),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::NotFound, name)),
+      }
+    }
+  }
+
+  #[test]
+  fn include_is_resolved_and_inlined() {
+    let resolver = StubResolver;
+    let (output, errors) = preprocessor().run(
+      "before\n#include <foo.glsl>\nafter\n",
+      Some(&resolver),
+    );
+
+    assert!(errors.is_empty());
+    assert_eq!(output, "before\nint foo() { return 1; }\nafter\n");
+  }
+
+  #[test]
+  fn include_resolution_failure_is_reported() {
+    let resolver = StubResolver;
+    let (output, errors) = preprocessor().run("#include <missing.glsl>\n", Some(&resolver));
+
+    assert_eq!(output, "");
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+      &errors[0],
+      PreprocessorError::IncludeResolutionFailed { path, .. } if path == "missing.glsl"
+    ));
+  }
+}
@@ -0,0 +1,204 @@
+//! Multi-file `#include` resolution.
+//!
+//! This crate has no notion of a filesystem, so resolving `#include` is opt-in: a caller that
+//! wants includes to actually pull in another source implements [`IncludeResolver`] and passes it
+//! to [`Preprocessor::events`]/[`Preprocessor::run`], which drive
+//! [`Preprocessor::push_include`]/[`Preprocessor::pop_include`] around each included source, the
+//! way moore-svlog keeps a stack of input `Stream`s and saltwater nests a nested `FileProcessor`.
+//! Without a resolver, `#include` is simply passed through untouched, as before.
+
+use crate::syntax::{IncludeDirective, Path, PragmaDirective};
+use crate::{Preprocessor, PreprocessorError};
+
+/// Resolves an `#include` path to the contents of the file it designates.
+pub trait IncludeResolver {
+  /// Resolve `path`, found in an `#include` directive inside `from`, to the canonical name and
+  /// contents of the source it designates.
+  ///
+  /// `from` is the canonical name of the source the directive appears in (the root input’s name
+  /// for a top-level `#include`), letting a resolver search relative to it. Whether `path` was
+  /// written with angle brackets or double quotes is carried by its [`Path`] variant, so a
+  /// resolver can choose a different set of search roots for each form.
+  fn resolve(&self, path: &Path, from: &str) -> Result<(String, String), std::io::Error>;
+}
+
+impl Preprocessor {
+  /// Canonical name of the source currently being preprocessed, i.e. the top of the include
+  /// stack, or `None` when preprocessing the root input.
+  pub fn current_include(&self) -> Option<&str> {
+    self.include_stack.last().map(String::as_str)
+  }
+
+  /// Resolve and enter an `#include`d source.
+  ///
+  /// On success, returns the contents to preprocess next, with the resolved source pushed on top
+  /// of the include stack. The caller must call [`Preprocessor::pop_include`] once it reaches the
+  /// end of that source, to resume the parent exactly where it left off. Returns `Ok(None)` when
+  /// the resolved source was already brought in under a `#pragma once` and must be skipped this
+  /// time.
+  pub fn push_include(
+    &mut self,
+    resolver: &dyn IncludeResolver,
+    directive: &IncludeDirective,
+    from: &str,
+  ) -> Result<Option<String>, PreprocessorError> {
+    if self.include_stack.len() >= self.opt.max_include_depth {
+      return Err(PreprocessorError::IncludeTooDeep {
+        path: path_to_string(&directive.path),
+      });
+    }
+
+    let (canonical_name, contents) =
+      resolver
+        .resolve(&directive.path, from)
+        .map_err(|error| PreprocessorError::IncludeResolutionFailed {
+          path: path_to_string(&directive.path),
+          message: error.to_string(),
+        })?;
+
+    if self.pragma_once_seen.contains(&canonical_name) {
+      return Ok(None);
+    }
+
+    if self.include_stack.contains(&canonical_name) {
+      return Err(PreprocessorError::IncludeCycle { path: canonical_name });
+    }
+
+    self.include_stack.push(canonical_name);
+
+    Ok(Some(contents))
+  }
+
+  /// Leave the innermost `#include`d source, resuming its parent exactly where it left off.
+  pub fn pop_include(&mut self) {
+    self.include_stack.pop();
+  }
+
+  /// Record a `#pragma once` in the source currently at the top of the include stack, so a later
+  /// `#include` of the same canonical path is skipped.
+  fn mark_pragma_once(&mut self) {
+    if let Some(name) = self.include_stack.last() {
+      self.pragma_once_seen.insert(name.clone());
+    }
+  }
+
+  /// Interpret a `#pragma` directive for `#pragma once` purposes; any other pragma is left for
+  /// the rest of the crate to pass through untouched.
+  pub(crate) fn handle_pragma(&mut self, directive: &PragmaDirective) {
+    if directive.command.trim() == "once" {
+      self.mark_pragma_once();
+    }
+  }
+}
+
+fn path_to_string(path: &Path) -> String {
+  match path {
+    Path::Absolute(p) | Path::Relative(p) => p.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::syntax::Path;
+  use crate::PreprocessorOpt;
+
+  /// Resolver that treats a relative `#include` path as a canonical name directly and looks its
+  /// contents up in a fixed table, failing like a real filesystem-backed resolver would on a miss.
+  struct MapResolver(Vec<(&'static str, &'static str)>);
+
+  impl IncludeResolver for MapResolver {
+    fn resolve(&self, path: &Path, _from: &str) -> Result<(String, String), std::io::Error> {
+      let name = match path {
+        Path::Absolute(p) | Path::Relative(p) => p.as_str(),
+      };
+
+      self
+        .0
+        .iter()
+        .find(|(candidate, _)| *candidate == name)
+        .map(|(name, contents)| (name.to_string(), contents.to_string()))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, name.to_owned()))
+    }
+  }
+
+  fn directive(path: &str) -> IncludeDirective {
+    IncludeDirective {
+      path: Path::Relative(path.to_owned()),
+    }
+  }
+
+  #[test]
+  fn push_pop_include_round_trip() {
+    let resolver = MapResolver(vec![("a.glsl", "void a() {}\n")]);
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+
+    assert_eq!(pp.current_include(), None);
+
+    let contents = pp.push_include(&resolver, &directive("a.glsl"), "<input>").unwrap();
+    assert_eq!(contents, Some("void a() {}\n".to_owned()));
+    assert_eq!(pp.current_include(), Some("a.glsl"));
+
+    pp.pop_include();
+    assert_eq!(pp.current_include(), None);
+  }
+
+  #[test]
+  fn pragma_once_skips_second_include() {
+    let resolver = MapResolver(vec![("a.glsl", "void a() {}\n")]);
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+
+    pp.push_include(&resolver, &directive("a.glsl"), "<input>").unwrap();
+    pp.handle_pragma(&PragmaDirective {
+      command: "once".to_owned(),
+    });
+    pp.pop_include();
+
+    let contents = pp.push_include(&resolver, &directive("a.glsl"), "<input>").unwrap();
+    assert_eq!(contents, None);
+  }
+
+  #[test]
+  fn cycle_detection() {
+    let resolver = MapResolver(vec![("a.glsl", "...")]);
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+
+    pp.push_include(&resolver, &directive("a.glsl"), "<input>").unwrap();
+
+    let error = pp.push_include(&resolver, &directive("a.glsl"), "a.glsl").unwrap_err();
+    assert_eq!(
+      error,
+      PreprocessorError::IncludeCycle {
+        path: "a.glsl".to_owned()
+      }
+    );
+  }
+
+  #[test]
+  fn max_include_depth_is_enforced() {
+    let resolver = MapResolver(vec![("a.glsl", "...")]);
+    let mut pp = Preprocessor::new(PreprocessorOpt::default().with_max_include_depth(1));
+
+    pp.push_include(&resolver, &directive("a.glsl"), "<input>").unwrap();
+
+    let error = pp.push_include(&resolver, &directive("a.glsl"), "a.glsl").unwrap_err();
+    assert_eq!(
+      error,
+      PreprocessorError::IncludeTooDeep {
+        path: "a.glsl".to_owned()
+      }
+    );
+  }
+
+  #[test]
+  fn resolution_failure_is_reported() {
+    let resolver = MapResolver(vec![]);
+    let mut pp = Preprocessor::new(PreprocessorOpt::default());
+
+    let error = pp
+      .push_include(&resolver, &directive("missing.glsl"), "<input>")
+      .unwrap_err();
+
+    assert!(matches!(error, PreprocessorError::IncludeResolutionFailed { path, .. } if path == "missing.glsl"));
+  }
+}
@@ -5,7 +5,6 @@
 //! some:
 //!
 //! - Not interpreted:
-//!   - `#include`.
 //!   - `#line`.
 //!   - `#pragma`.
 //!   - `#version`.
@@ -13,17 +12,32 @@
 //! - Interpreted:
 //!   - `#define`, `#undef`.
 //!   - `#if`, `#ifdef`, `#ifndef`, `#elseif`, `#else`, `#endif`.
+//!   - `#include`, but only when an [`IncludeResolver`] is configured (see below).
 //!
 //! Non-interpreted CPP directives are meaningful for the rest of the parsing, lexing and
-//! compilation stages, thus they are passed along with the rest of the input. It’s especially
-//! important for `#include`, for instance — that is not officially recognized by the GLSL
-//! preprocessor but can has a wide spread usage. You will be required to resolve those includes by
-//! yourself, as it’s not in the scope of this crate.
+//! compilation stages, thus they are passed along with the rest of the input. Some of them are
+//! still read on the way through: `#line` remaps what `__LINE__`/`__FILE__` report, `#version`
+//! sets `__VERSION__`, and `#pragma once` marks the current `#include`d source as included once.
+//!
+//! `#include` is not officially recognized by the GLSL preprocessor but sees wide spread usage, so
+//! resolving it is opt-in: without an [`IncludeResolver`] configured, it is passed through
+//! untouched, same as any other non-interpreted directive, leaving resolution to the rest of the
+//! pipeline. Pass one to [`Preprocessor::events`]/[`Preprocessor::run`] to have a reached
+//! `#include` resolved and its contents preprocessed in place instead.
 
+mod builtins;
+mod eval;
+mod event;
+mod include;
+mod macros;
 mod parser;
+pub mod syntax;
+
+pub use event::{Event, OutputToken};
+pub use include::IncludeResolver;
 
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// Runtime error while preprocessing.
 #[derive(Clone, Debug, PartialEq)]
@@ -42,6 +56,35 @@ pub enum PreprocessorError {
   UndefineUnknownSymbol { ident: String },
   /// Non-matching conditional, like `#if` vs. `#endif`.
   NonMatchingConditional,
+  /// A `#if`/`#elseif` constant expression divided (or took the remainder) by zero.
+  DivisionByZero,
+  /// A function-like macro was invoked with a different number of arguments than it was
+  /// declared with.
+  MacroArgumentCountMismatch {
+    /// Name of the macro.
+    ident: String,
+    /// Number of parameters the macro was declared with.
+    expected: usize,
+    /// Number of arguments the invocation actually provided.
+    got: usize,
+  },
+  /// An `#include` resolved to a source that is already on the include stack.
+  IncludeCycle {
+    /// Canonical path of the source that would have been included a second time.
+    path: String,
+  },
+  /// An `#include` chain went deeper than `PreprocessorOpt::max_include_depth`.
+  IncludeTooDeep {
+    /// Path as written in the offending `#include` directive.
+    path: String,
+  },
+  /// The configured [`IncludeResolver`] failed to resolve an `#include`.
+  IncludeResolutionFailed {
+    /// Path as written in the offending `#include` directive.
+    path: String,
+    /// Message describing why resolution failed.
+    message: String,
+  },
   /// Code-driven error.
   CodeDriven(String),
 }
@@ -60,6 +103,13 @@ pub enum Defined {
   },
 }
 
+impl Default for Preprocessor {
+  /// Equivalent to [`Preprocessor::new`] with a default [`PreprocessorOpt`].
+  fn default() -> Self {
+    Preprocessor::new(PreprocessorOpt::default())
+  }
+}
+
 /// The preprocessor.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Preprocessor {
@@ -70,11 +120,45 @@ pub struct Preprocessor {
   /// Currently defined values; map an identifier to a defined symbol.
   defined_syms: HashMap<String, Defined>,
   /// Currently active conditional code; `true` means that we must continue parsing; `false` that
-  /// we should be ignoring code until we meet either a new conditional, or `#endif`.
+  /// we should be ignoring code until we meet either a new conditional, or `#endif`. Each entry
+  /// already accounts for the state of its enclosing conditional.
   conditional_stack: Vec<bool>,
+  /// For each entry in `conditional_stack`, whether a branch of that `#if`/`#elseif`/`#else`
+  /// group has already been taken; consulted to decide whether a later `#elseif`/`#else` may
+  /// still fire.
+  conditional_matched: Vec<bool>,
+  /// Canonical names of the sources currently being preprocessed, innermost last; the root input
+  /// is implicit and never appears here. See [`IncludeResolver`].
+  include_stack: Vec<String>,
+  /// Canonical names of sources already brought in under a `#pragma once`.
+  pragma_once_seen: HashSet<String>,
+  /// Offset applied to `Parser`’s physical line number to account for an interpreted `#line`
+  /// directive; see [`Preprocessor::report_line`].
+  line_offset: i64,
+  /// Source-string number set by the most recent `#line N M` directive’s optional `M`, consulted
+  /// by `__FILE__` outside an active `#include`.
+  source_string_number: Option<u32>,
+  /// Version declared by the most recent `#version` directive, consulted by `__VERSION__`.
+  version: Option<u16>,
 }
 
 impl Preprocessor {
+  /// Create a new preprocessor configured by `opt`.
+  pub fn new(opt: PreprocessorOpt) -> Self {
+    Preprocessor {
+      opt,
+      runtime_errors: Vec::new(),
+      defined_syms: HashMap::new(),
+      conditional_stack: Vec::new(),
+      conditional_matched: Vec::new(),
+      include_stack: Vec::new(),
+      pragma_once_seen: HashSet::new(),
+      line_offset: 0,
+      source_string_number: None,
+      version: None,
+    }
+  }
+
   /// Define a symbol.
   fn define_sym(&mut self, ident: String, value: Defined) {
     match self.defined_syms.entry(ident.clone()) {
@@ -113,15 +197,82 @@ impl Preprocessor {
     }
   }
 
-  /// Enter a conditional scope.
-  fn enter_conditional(&mut self, condition: bool) {
-    self.conditional_stack.push(condition);
+  /// Whether the group enclosing the innermost conditional group (if any) is itself active, i.e.
+  /// whether an `#elseif`/`#else` of the innermost group may be taken at all.
+  fn parent_conditional_active(&self) -> bool {
+    let len = self.conditional_stack.len();
+
+    if len <= 1 {
+      true
+    } else {
+      self.conditional_stack[len - 2]
+    }
+  }
+
+  /// Enter an `#if` conditional scope, evaluating its constant-expression condition.
+  fn enter_if(&mut self, condition: &str, reported_line: i64) {
+    let taken = !self.is_ignoring() && self.eval_condition(condition, reported_line);
+    self.conditional_stack.push(taken);
+    self.conditional_matched.push(taken);
   }
 
-  /// Leave a conditional scope.
+  /// Enter an `#ifdef` conditional scope.
+  fn enter_ifdef(&mut self, ident: &str) {
+    let taken = !self.is_ignoring() && self.defined_syms.contains_key(ident);
+    self.conditional_stack.push(taken);
+    self.conditional_matched.push(taken);
+  }
+
+  /// Enter an `#ifndef` conditional scope.
+  fn enter_ifndef(&mut self, ident: &str) {
+    let taken = !self.is_ignoring() && !self.defined_syms.contains_key(ident);
+    self.conditional_stack.push(taken);
+    self.conditional_matched.push(taken);
+  }
+
+  /// Enter an `#elseif` branch. Its condition is only evaluated when no prior branch of the same
+  /// group has already been taken.
+  fn enter_elseif(&mut self, condition: &str, reported_line: i64) {
+    let parent_active = self.parent_conditional_active();
+    let already_matched = self.conditional_matched.last().cloned().unwrap_or(false);
+    let may_take = parent_active && !already_matched;
+
+    // `eval_condition` expands macros in `condition`, which refuses to run while `is_ignoring()`
+    // holds; at this point `conditional_stack`'s top still reflects the previous sibling branch
+    // (typically `false`), not whether this `#elseif` is actually allowed to fire. Mark the group
+    // active first so the expansion sees the right state.
+    if let Some(active) = self.conditional_stack.last_mut() {
+      *active = may_take;
+    }
+
+    let taken = may_take && self.eval_condition(condition, reported_line);
+
+    if let Some(active) = self.conditional_stack.last_mut() {
+      *active = taken;
+    }
+
+    if let Some(matched) = self.conditional_matched.last_mut() {
+      *matched = *matched || taken;
+    }
+  }
+
+  /// Enter an `#else` branch. It is active only when no prior branch of the same group has
+  /// already been taken.
+  fn enter_else(&mut self) {
+    let parent_active = self.parent_conditional_active();
+    let already_matched = self.conditional_matched.last().cloned().unwrap_or(false);
+    let taken = parent_active && !already_matched;
+
+    if let Some(active) = self.conditional_stack.last_mut() {
+      *active = taken;
+    }
+  }
+
+  /// Leave a conditional scope, at `#endif`.
   ///
   /// Return the conditional we were in.
   fn leave_conditional(&mut self) -> Option<bool> {
+    self.conditional_matched.pop();
     let cond = self.conditional_stack.pop();
 
     if cond.is_none() {
@@ -136,7 +287,7 @@ impl Preprocessor {
   /// Check whether we should be interpreting the input or just ignore it. Typical cases of ignoring
   /// is inside `#if` where the condition is held false.
   fn is_ignoring(&self) -> bool {
-    self.conditional_stack.last().cloned().unwrap_or(true)
+    !self.conditional_stack.last().cloned().unwrap_or(true)
   }
 
   /// Make a preprocessor error.
@@ -150,11 +301,49 @@ impl Preprocessor {
   // pub fn run<I>(self, input: I) -> Result<Output, PreprocessorError>
 }
 
+/// Options to configure a [`Preprocessor`] with, passed to [`Preprocessor::new`].
+///
+/// # Example
+///
+/// ```
+/// use rcpp::{DefineMethod, PreprocessorOpt};
+///
+/// let opt = PreprocessorOpt::default()
+///   .with_define_method(DefineMethod::FailOnOverride)
+///   .with_max_include_depth(64);
+/// ```
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[non_exhaustive]
-struct PreprocessorOpt {
+pub struct PreprocessorOpt {
   /// [`DefineMethod`] to use everytime a `#define` is encountered.
   define_method: DefineMethod,
+  /// Maximum depth of nested `#include`s before [`PreprocessorError::IncludeTooDeep`] is raised.
+  max_include_depth: usize,
+}
+
+impl Default for PreprocessorOpt {
+  /// Defaults to [`DefineMethod::Override`] and a maximum include depth of 200.
+  fn default() -> Self {
+    PreprocessorOpt {
+      define_method: DefineMethod::Override,
+      max_include_depth: 200,
+    }
+  }
+}
+
+impl PreprocessorOpt {
+  /// Set the [`DefineMethod`] to use everytime a `#define` is encountered.
+  pub fn with_define_method(mut self, define_method: DefineMethod) -> Self {
+    self.define_method = define_method;
+    self
+  }
+
+  /// Set the maximum depth of nested `#include`s before [`PreprocessorError::IncludeTooDeep`] is
+  /// raised.
+  pub fn with_max_include_depth(mut self, max_include_depth: usize) -> Self {
+    self.max_include_depth = max_include_depth;
+    self
+  }
 }
 
 /// Method to apply when running the `#define` directive.
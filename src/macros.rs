@@ -0,0 +1,546 @@
+//! Macro expansion engine.
+//!
+//! Substitutes `#define`d object-like and function-like macros into the ordinary (non-directive)
+//! input stream and rescans the result for further invocations, the way saltwater’s
+//! `MacroReplacer` and moore’s `macro_stack` do. A per-token hide set (the “blue paint” rule)
+//! prevents a macro from recursively expanding into itself.
+
+use std::collections::HashSet;
+
+use crate::{Defined, Preprocessor, PreprocessorError};
+
+/// Names of macros currently being expanded somewhere up the recursion chain; a name found in
+/// here is left as a plain identifier instead of being expanded again.
+type HideSet = HashSet<String>;
+
+impl Preprocessor {
+  /// Expand every macro invocation found in `input`, rescanning expansions until no more macros
+  /// apply. `reported_line` is the value `__LINE__` should expand to at this call site. Must not
+  /// be called while [`Preprocessor::is_ignoring`] holds, as skipped conditional branches must not
+  /// have their macros substituted.
+  pub(crate) fn expand_macros(&self, input: &str, reported_line: i64) -> Result<String, PreprocessorError> {
+    if self.is_ignoring() {
+      return Ok(input.to_owned());
+    }
+
+    self.expand_macros_rec(input, &HideSet::new(), reported_line)
+  }
+
+  /// Rescan `input` for macro invocations, replacing each one in place and resuming the scan from
+  /// the start of its replacement rather than recursing on the replacement in isolation. This is
+  /// what lets a macro whose body names another, function-like macro be called with arguments
+  /// coming from whatever follows the first macro in the outer stream — standard C rescanning
+  /// behavior that an independent per-invocation recursion can’t produce, since the replacement
+  /// and what follows it only ever meet inside the same scan. Each token carries its own hide set,
+  /// since tokens introduced by different expansions (or never expanded at all) must not share one.
+  fn expand_macros_rec(&self, input: &str, hide_set: &HideSet, reported_line: i64) -> Result<String, PreprocessorError> {
+    let mut tokens: Vec<ScanTok> = lex(input)
+      .into_iter()
+      .map(|tok| ScanTok::new(tok, hide_set.clone()))
+      .collect();
+    let mut i = 0;
+
+    while i < tokens.len() {
+      let ScanTok { tok, hide_set } = &tokens[i];
+
+      let ident = match tok {
+        Tok::Ident(ident) if !hide_set.contains(ident) => ident.clone(),
+
+        _ => {
+          i += 1;
+          continue;
+        }
+      };
+
+      let plain = plain_toks(&tokens);
+
+      match self.defined_syms.get(&ident) {
+        Some(Defined::Object(body)) => {
+          let mut inner_hide_set = hide_set.clone();
+          inner_hide_set.insert(ident);
+          let replacement = lex(body)
+            .into_iter()
+            .map(|tok| ScanTok::new(tok, inner_hide_set.clone()));
+
+          tokens.splice(i..i + 1, replacement);
+          // Rescan from the start of the replacement, so a call-opening `(` that follows this
+          // identifier in the outer stream is seen together with whatever the body ends with.
+        }
+
+        Some(Defined::Function { arg, body }) if call_follows(&plain, i + 1) => {
+          let (args, next) = parse_call_args(&ident, &plain, i + 1, arg.len())?;
+
+          if args.len() != arg.len() {
+            return Err(PreprocessorError::MacroArgumentCountMismatch {
+              ident,
+              expected: arg.len(),
+              got: args.len(),
+            });
+          }
+
+          let mut inner_hide_set = hide_set.clone();
+          inner_hide_set.insert(ident.clone());
+
+          // Arguments are expanded against the invocation-site hide set, *not* `inner_hide_set`:
+          // the macro's own name must only paint the tokens of its substituted body, or a macro
+          // nested in its own argument (`MAX(MAX(1, 2), 3)`) would find itself already hidden and
+          // never expand.
+          let substituted = self.substitute_function_macro(arg, body, &args, hide_set, reported_line)?;
+          let replacement = lex(&substituted)
+            .into_iter()
+            .map(|tok| ScanTok::new(tok, inner_hide_set.clone()));
+
+          tokens.splice(i..next, replacement);
+        }
+
+        Some(Defined::Function { .. }) => {
+          // Declared but not invoked here (no `(` follows): left as a plain identifier.
+          i += 1;
+        }
+
+        None => {
+          if let Some(value) = self.dynamic_builtin_value(&ident, reported_line) {
+            let hide_set = hide_set.clone();
+            let replacement = lex(&value).into_iter().map(|tok| ScanTok::new(tok, hide_set.clone()));
+
+            tokens.splice(i..i + 1, replacement);
+          }
+
+          i += 1;
+        }
+      }
+    }
+
+    Ok(tokens.iter().map(|scan_tok| scan_tok.tok.spelling()).collect())
+  }
+
+  /// Whether `input` contains a function-like macro invocation whose argument list is not yet
+  /// closed, e.g. `FOO(1,` with the matching `)` still to come. The event driver uses this to
+  /// join physical lines back into one logical line before handing them to
+  /// [`Preprocessor::expand_macros`], since a call site is free to spread its argument list over
+  /// several of them.
+  pub(crate) fn has_unterminated_invocation(&self, input: &str) -> bool {
+    let tokens = lex(input);
+    let mut i = 0;
+
+    while i < tokens.len() {
+      match &tokens[i] {
+        Tok::Ident(ident) if call_follows(&tokens, i + 1) => match self.defined_syms.get(ident) {
+          Some(Defined::Function { arg, .. }) => match parse_call_args(ident, &tokens, i + 1, arg.len()) {
+            Ok((_, next)) => i = next,
+            Err(_) => return true,
+          },
+
+          _ => i += 1,
+        },
+
+        _ => i += 1,
+      }
+    }
+
+    false
+  }
+
+  /// Substitute `params` with `args` into a function-like macro `body`, honoring `#param`
+  /// stringization and `a ## b` token pasting. A parameter not adjacent to `#`/`##` is substituted
+  /// with its fully macro-expanded argument; one that is adjacent to `#` or `##` uses the
+  /// argument’s raw, unexpanded spelling, per the usual C rule.
+  fn substitute_function_macro(
+    &self,
+    params: &[String],
+    body: &str,
+    args: &[String],
+    hide_set: &HideSet,
+    reported_line: i64,
+  ) -> Result<String, PreprocessorError> {
+    let tokens = strip_hash_whitespace(&lex(body));
+    let mut expanded_args: Vec<Option<String>> = vec![None; args.len()];
+    let mut out = String::with_capacity(body.len());
+    let mut i = 0;
+
+    while i < tokens.len() {
+      match &tokens[i] {
+        Tok::Hash => {
+          if let Some(Tok::Ident(name)) = tokens.get(i + 1) {
+            if let Some(idx) = params.iter().position(|p| p == name) {
+              out.push_str(&stringize(&args[idx]));
+              i += 2;
+              continue;
+            }
+          }
+
+          out.push('#');
+          i += 1;
+        }
+
+        Tok::HashHash => {
+          // The operator itself carries no spelling; its effect is just not separating its two
+          // operands, which is already what happens since nothing is emitted for it here.
+          i += 1;
+        }
+
+        Tok::Ident(name) => {
+          if let Some(idx) = params.iter().position(|p| p == name) {
+            let pasted = matches!(tokens.get(i.wrapping_sub(1)), Some(Tok::HashHash))
+              || matches!(tokens.get(i + 1), Some(Tok::HashHash));
+
+            if pasted {
+              out.push_str(&args[idx]);
+            } else {
+              if expanded_args[idx].is_none() {
+                expanded_args[idx] = Some(self.expand_macros_rec(&args[idx], hide_set, reported_line)?);
+              }
+
+              out.push_str(expanded_args[idx].as_ref().unwrap());
+            }
+          } else {
+            out.push_str(name);
+          }
+
+          i += 1;
+        }
+
+        tok => {
+          out.push_str(&tok.spelling());
+          i += 1;
+        }
+      }
+    }
+
+    Ok(out)
+  }
+}
+
+/// A lexical token for the macro-expansion engine. `Other` runs preserve spelling byte-for-byte
+/// (whitespace, numeric and string literals, operators, …) since expansion must not disturb
+/// anything it doesn’t itself substitute.
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+  Ident(String),
+  LParen,
+  RParen,
+  Comma,
+  Hash,
+  HashHash,
+  Other(String),
+}
+
+impl Tok {
+  fn spelling(&self) -> String {
+    match self {
+      Tok::Ident(s) | Tok::Other(s) => s.clone(),
+      Tok::LParen => "(".to_owned(),
+      Tok::RParen => ")".to_owned(),
+      Tok::Comma => ",".to_owned(),
+      Tok::Hash => "#".to_owned(),
+      Tok::HashHash => "##".to_owned(),
+    }
+  }
+}
+
+/// A token paired with the hide set it carries. Tokens introduced by different macro expansions
+/// (or never expanded at all) each need their own hide set, since painting one token with a name
+/// must not stop an unrelated token spelled the same way from expanding later.
+#[derive(Clone, Debug, PartialEq)]
+struct ScanTok {
+  tok: Tok,
+  hide_set: HideSet,
+}
+
+impl ScanTok {
+  fn new(tok: Tok, hide_set: HideSet) -> Self {
+    ScanTok { tok, hide_set }
+  }
+}
+
+/// Strip the hide sets back off, for the `call_follows`/`parse_call_args` helpers, which only
+/// ever need to look at spelling and token kind.
+fn plain_toks(tokens: &[ScanTok]) -> Vec<Tok> {
+  tokens.iter().map(|scan_tok| scan_tok.tok.clone()).collect()
+}
+
+fn lex(input: &str) -> Vec<Tok> {
+  let chars: Vec<char> = input.chars().collect();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+
+  while i < chars.len() {
+    let c = chars[i];
+
+    if c.is_alphabetic() || c == '_' {
+      let start = i;
+
+      while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+        i += 1;
+      }
+
+      tokens.push(Tok::Ident(chars[start..i].iter().collect()));
+    } else if c == '"' {
+      let start = i;
+      i += 1;
+
+      while i < chars.len() && chars[i] != '"' {
+        i += if chars[i] == '\\' { 2 } else { 1 };
+      }
+
+      i = (i + 1).min(chars.len());
+      tokens.push(Tok::Other(chars[start..i].iter().collect()));
+    } else if c.is_ascii_digit() {
+      let start = i;
+
+      while i < chars.len() && (chars[i].is_alphanumeric() || matches!(chars[i], '_' | '.')) {
+        i += 1;
+      }
+
+      tokens.push(Tok::Other(chars[start..i].iter().collect()));
+    } else if c == '(' {
+      tokens.push(Tok::LParen);
+      i += 1;
+    } else if c == ')' {
+      tokens.push(Tok::RParen);
+      i += 1;
+    } else if c == ',' {
+      tokens.push(Tok::Comma);
+      i += 1;
+    } else if c == '#' {
+      if chars.get(i + 1) == Some(&'#') {
+        tokens.push(Tok::HashHash);
+        i += 2;
+      } else {
+        tokens.push(Tok::Hash);
+        i += 1;
+      }
+    } else {
+      let start = i;
+      i += 1;
+
+      while i < chars.len()
+        && !matches!(chars[i], '#' | '(' | ')' | ',' | '"')
+        && !chars[i].is_alphanumeric()
+        && chars[i] != '_'
+      {
+        i += 1;
+      }
+
+      tokens.push(Tok::Other(chars[start..i].iter().collect()));
+    }
+  }
+
+  tokens
+}
+
+/// Drop whitespace-only `Other` tokens directly touching a `#`/`##` operator, so stringization and
+/// pasting see their operand immediately next to the operator regardless of source spacing.
+fn strip_hash_whitespace(tokens: &[Tok]) -> Vec<Tok> {
+  let mut out: Vec<Tok> = Vec::with_capacity(tokens.len());
+
+  for (idx, tok) in tokens.iter().enumerate() {
+    if let Tok::Other(s) = tok {
+      if s.trim().is_empty() {
+        let before_is_hash = matches!(out.last(), Some(Tok::Hash) | Some(Tok::HashHash));
+        let after_is_hash = matches!(tokens.get(idx + 1), Some(Tok::Hash) | Some(Tok::HashHash));
+
+        if before_is_hash || after_is_hash {
+          continue;
+        }
+      }
+    }
+
+    out.push(tok.clone());
+  }
+
+  out
+}
+
+/// Whether the token at `i` (skipping over whitespace) is the opening `(` of a macro invocation.
+fn call_follows(tokens: &[Tok], mut i: usize) -> bool {
+  while let Some(Tok::Other(s)) = tokens.get(i) {
+    if s.trim().is_empty() {
+      i += 1;
+    } else {
+      break;
+    }
+  }
+
+  matches!(tokens.get(i), Some(Tok::LParen))
+}
+
+/// Parse the argument list of a function-like macro invocation starting at `i` (which may be
+/// preceded by whitespace before the opening `(`). Return the raw (unexpanded) spelling of each
+/// argument and the index right after the closing `)`. `param_count` is the number of parameters
+/// the macro being invoked declares, consulted to decide whether `()` is a zero-argument call or a
+/// one-argument call with an empty argument (see the comment below).
+fn parse_call_args(
+  ident: &str,
+  tokens: &[Tok],
+  mut i: usize,
+  param_count: usize,
+) -> Result<(Vec<String>, usize), PreprocessorError> {
+  while let Some(Tok::Other(s)) = tokens.get(i) {
+    if s.trim().is_empty() {
+      i += 1;
+    } else {
+      break;
+    }
+  }
+
+  if !matches!(tokens.get(i), Some(Tok::LParen)) {
+    return Err(PreprocessorError::CodeDriven(format!(
+      "expected '(' in invocation of function-like macro `{}`",
+      ident
+    )));
+  }
+
+  i += 1;
+
+  let mut args = Vec::new();
+  let mut current = String::new();
+  let mut depth = 0usize;
+
+  loop {
+    match tokens.get(i) {
+      None => {
+        return Err(PreprocessorError::CodeDriven(format!(
+          "unterminated invocation of function-like macro `{}`",
+          ident
+        )))
+      }
+
+      Some(Tok::LParen) => {
+        depth += 1;
+        current.push('(');
+        i += 1;
+      }
+
+      Some(Tok::RParen) if depth == 0 => {
+        args.push(current.trim().to_owned());
+        i += 1;
+        break;
+      }
+
+      Some(Tok::RParen) => {
+        depth -= 1;
+        current.push(')');
+        i += 1;
+      }
+
+      Some(Tok::Comma) if depth == 0 => {
+        args.push(current.trim().to_owned());
+        current.clear();
+        i += 1;
+      }
+
+      Some(tok) => {
+        current.push_str(&tok.spelling());
+        i += 1;
+      }
+    }
+  }
+
+  // `foo()` is a zero-argument call, not a one-argument call with an empty argument — but only
+  // when `foo` itself declares zero parameters; `F()` against `#define F(x) [x]` is one argument
+  // that happens to be empty, not an arity mismatch.
+  if param_count == 0 && args.len() == 1 && args[0].is_empty() {
+    args.clear();
+  }
+
+  Ok((args, i))
+}
+
+/// Turn an argument’s raw spelling into a string literal, escaping `"` and `\` as `#param`
+/// stringization requires.
+fn stringize(arg: &str) -> String {
+  let trimmed = arg.trim();
+  let mut out = String::with_capacity(trimmed.len() + 2);
+  out.push('"');
+
+  for c in trimmed.chars() {
+    if c == '"' || c == '\\' {
+      out.push('\\');
+    }
+
+    out.push(c);
+  }
+
+  out.push('"');
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{Preprocessor, PreprocessorOpt};
+
+  fn preprocessor() -> Preprocessor {
+    Preprocessor::new(PreprocessorOpt::default())
+  }
+
+  #[test]
+  fn stringize_operator() {
+    let (output, errors) = preprocessor().run("#define STR(x) #x\nchar *s = STR(hello world);\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, r#"char *s = "hello world";"#.to_owned() + "\n");
+  }
+
+  #[test]
+  fn token_pasting_operator() {
+    let (output, errors) = preprocessor().run("#define CAT(a, b) a##b\nint CAT(fo, o) = 1;\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int foo = 1;\n");
+  }
+
+  #[test]
+  fn argument_count_mismatch_errors() {
+    let (_, errors) = preprocessor().run("#define ADD(a, b) a+b\nint x = ADD(1);\n", None);
+    assert_eq!(
+      errors,
+      vec![crate::PreprocessorError::MacroArgumentCountMismatch {
+        ident: "ADD".to_owned(),
+        expected: 2,
+        got: 1,
+      }]
+    );
+  }
+
+  #[test]
+  fn self_referential_macro_is_not_re_expanded() {
+    let (output, errors) = preprocessor().run("#define FOO FOO + 1\nint x = FOO;\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int x = FOO + 1;\n");
+  }
+
+  #[test]
+  fn nested_self_invocation_in_argument_is_expanded() {
+    // A function-like macro invoked in its own argument (the common MAX/MIN/CLAMP pattern) must
+    // still have that inner call expanded: only the substituted body, not the argument list, is
+    // painted with the macro's own name.
+    let (output, errors) =
+      preprocessor().run("#define MAX(a, b) ((a)>(b)?(a):(b))\nint m = MAX(MAX(1, 2), 3);\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int m = ((((1)>(2)?(1):(2)))>(3)?(((1)>(2)?(1):(2))):(3));\n");
+  }
+
+  #[test]
+  fn empty_call_to_one_argument_macro_substitutes_empty_argument() {
+    let (output, errors) = preprocessor().run("#define F(x) [x]\nint x = F();\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int x = [];\n");
+  }
+
+  #[test]
+  fn empty_call_to_zero_argument_macro_is_not_a_mismatch() {
+    let (output, errors) = preprocessor().run("#define F() 42\nint x = F();\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int x = 42;\n");
+  }
+
+  #[test]
+  fn rescan_crosses_expansion_boundary() {
+    // `FOO` expands to the bare identifier `BAR`, and only the text that followed `FOO` in the
+    // outer stream supplies `BAR`'s argument list; this only works if expansion rescans the
+    // spliced-in replacement together with what follows it, rather than expanding `FOO`'s body in
+    // isolation.
+    let (output, errors) = preprocessor().run("#define FOO BAR\n#define BAR(x) x+1\nint r = FOO(5);\n", None);
+    assert!(errors.is_empty());
+    assert_eq!(output, "int r = 5+1;\n");
+  }
+}
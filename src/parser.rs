@@ -12,7 +12,7 @@ where
 }
 
 impl<'a> Parser<'a, std::str::Chars<'a>> {
-  fn new(s: &'a str) -> Self {
+  pub(crate) fn new(s: &'a str) -> Self {
     Parser {
       chars: s.chars().peekable(),
       line: 1,
@@ -21,11 +21,11 @@ impl<'a> Parser<'a, std::str::Chars<'a>> {
     }
   }
 
-  fn line(&self) -> usize {
+  pub(crate) fn line(&self) -> usize {
     self.line
   }
 
-  fn col(&self) -> usize {
+  pub(crate) fn col(&self) -> usize {
     self.col
   }
 }
@@ -47,7 +47,7 @@ where
     }
   }
 
-  fn char(&mut self) -> Option<char> {
+  pub(crate) fn char(&mut self) -> Option<char> {
     self.chars.next().and_then(|c| match c {
       '\\' => {
         // special case for \, as it marks a line break if the next character is a \n; in this